@@ -0,0 +1,89 @@
+//! Source-annotated diagnostics for macro-expansion errors.
+//!
+//! Wraps the `annotate-snippets` crate so that a [`ParserError`] raised while
+//! expanding an internal macro (`%define`, `%load`, `%expand`, ...) is shown
+//! with the offending source line, the file it came from, and a caret
+//! underlining the macro invocation, instead of a bare message.
+//!
+//! [`annotate`] is called from `SpecParser::parse_macro` around every macro
+//! dispatch; see its doc comment for how a nested re-entrant failure avoids
+//! being annotated twice.
+
+use crate::error::ParserError as PE;
+use annotate_snippets::{Annotation, AnnotationType, Renderer, Slice, Snippet, SourceAnnotation};
+use color_eyre::eyre::eyre;
+use std::path::Path;
+
+/// Convert a byte offset into `src` to a (line, column) pair, with a
+/// 1-indexed line and a 0-indexed column.
+///
+/// `column` is measured from the start of the *line containing `offset`*
+/// (found via `rfind('\n')`), not from the start of `src`.
+pub(crate) fn line_col(src: &str, offset: usize) -> (usize, usize) {
+	let front = &src[..offset];
+	let line = front.chars().filter(|c| *c == '\n').count() + 1;
+	let col = offset - front.rfind('\n').map_or(0, |i| i + 1);
+	(line, col)
+}
+
+/// Render a single-line, single-span diagnostic for a macro-expansion
+/// failure at byte range `[offset, offset + len)` of `src`.
+pub(crate) fn render(file: &Path, src: &str, offset: usize, len: usize, macro_name: &str, message: &str) -> String {
+	let (line_no, col) = line_col(src, offset);
+	let line_start = offset - col;
+	let line_end = src[offset..].find('\n').map_or(src.len(), |i| offset + i);
+	let line_text = &src[line_start..line_end];
+	let ann_start = offset - line_start;
+	let ann_end = (ann_start + len.max(1)).min(line_text.len());
+	let origin = format!("{}:{line_no}:{col}", file.display());
+	let title = format!("%{macro_name}: {message}");
+	let snippet = Snippet {
+		title: Some(Annotation { label: Some(&title), id: None, annotation_type: AnnotationType::Error }),
+		footer: vec![],
+		slices: vec![Slice {
+			source: line_text,
+			line_start: line_no,
+			origin: Some(&origin),
+			fold: false,
+			annotations: vec![SourceAnnotation { label: "", annotation_type: AnnotationType::Error, range: (ann_start, ann_end) }],
+		}],
+	};
+	let renderer = Renderer::plain();
+	let rendered = renderer.render(snippet);
+	rendered.to_string()
+}
+
+/// Re-render `err` as a source-annotated diagnostic anchored at `[start,
+/// pos)` of `r`'s underlying buffer.
+///
+/// Called from `SpecParser::parse_macro` (the only place
+/// [`crate::macros::MacroType::Internal`] is ever invoked) around every
+/// macro dispatch. Internal macros that call one another directly as plain
+/// functions (e.g. `global` calling `define`) never go through dispatch
+/// again, so those failures are annotated once. Macros that instead
+/// re-enter `parse_macro` for their own operand text (`expr`, `expand`,
+/// `uncompress`, `shell_expand`) can have a nested failure annotated once
+/// at the inner dispatch and then bubble up through an outer one. If `err`
+/// is already a rendered snippet — detected via [`is_rendered_snippet`] — it
+/// is returned unchanged instead of being boxed a second time, which would
+/// otherwise nest one ASCII-art snippet inside another.
+pub(crate) fn annotate<R: ?Sized>(r: &crate::util::Consumer<R>, start: usize, macro_name: &str, err: PE) -> PE {
+	let message = err.to_string();
+	if is_rendered_snippet(&message) {
+		return err;
+	}
+	let src = r.s.lock();
+	let rendered = render(&r.file, &src, start, r.pos.saturating_sub(start), macro_name, &message);
+	eyre!("{rendered}").into()
+}
+
+/// Whether `message` is already the output of [`render`], i.e. this error
+/// has already been annotated once and shouldn't be wrapped again.
+///
+/// Checked against the origin line's `--> file:line:col` marker rather than
+/// a fixed-width `"\n  --> "` substring, since `Renderer`'s left gutter is
+/// padded to the width of the largest line number in the snippet and so
+/// isn't a fixed number of spaces.
+fn is_rendered_snippet(message: &str) -> bool {
+	message.lines().any(|line| line.trim_start().starts_with("--> "))
+}
@@ -33,6 +33,597 @@ impl From<&str> for MacroType {
 	}
 }
 
+/// A typed value produced by evaluating a `%expr`/`%[...]` expression.
+///
+/// `Ver` is kept distinct from `Str` because `v"..."` literals compare using
+/// rpm's version-ordering rules rather than plain lexicographic ordering.
+#[derive(Debug, Clone, PartialEq)]
+enum ExprValue {
+	Int(i64),
+	Str(String),
+	Ver(String),
+}
+
+impl ExprValue {
+	fn truthy(&self) -> bool {
+		match self {
+			Self::Int(i) => *i != 0,
+			Self::Str(s) | Self::Ver(s) => !s.is_empty(),
+		}
+	}
+	fn render(&self) -> String {
+		match self {
+			Self::Int(i) => i.to_string().into(),
+			Self::Str(s) | Self::Ver(s) => s.clone(),
+		}
+	}
+	fn add(self, rhs: Self) -> Result<Self, PE> {
+		match (self, rhs) {
+			(Self::Int(a), Self::Int(b)) => Ok(Self::Int(a + b)),
+			(Self::Str(mut a), Self::Str(b)) => {
+				a.push_str(&b);
+				Ok(Self::Str(a))
+			}
+			_ => Err(eyre!("%expr: `+` requires two integers or two strings").into()),
+		}
+	}
+	fn sub(self, rhs: Self) -> Result<Self, PE> {
+		match (self, rhs) {
+			(Self::Int(a), Self::Int(b)) => Ok(Self::Int(a - b)),
+			_ => Err(eyre!("%expr: `-` is only defined between integers").into()),
+		}
+	}
+	fn mul(self, rhs: Self) -> Result<Self, PE> {
+		match (self, rhs) {
+			(Self::Int(a), Self::Int(b)) => Ok(Self::Int(a * b)),
+			_ => Err(eyre!("%expr: `*` is only defined between integers").into()),
+		}
+	}
+	fn div(self, rhs: Self) -> Result<Self, PE> {
+		match (self, rhs) {
+			(Self::Int(_), Self::Int(0)) => Err(eyre!("%expr: division by zero").into()),
+			(Self::Int(a), Self::Int(b)) => Ok(Self::Int(a / b)),
+			_ => Err(eyre!("%expr: `/` is only defined between integers").into()),
+		}
+	}
+	fn modulo(self, rhs: Self) -> Result<Self, PE> {
+		match (self, rhs) {
+			(Self::Int(_), Self::Int(0)) => Err(eyre!("%expr: modulo by zero").into()),
+			(Self::Int(a), Self::Int(b)) => Ok(Self::Int(a % b)),
+			_ => Err(eyre!("%expr: `%` is only defined between integers").into()),
+		}
+	}
+	fn neg(self) -> Result<Self, PE> {
+		match self {
+			Self::Int(i) => Ok(Self::Int(-i)),
+			_ => Err(eyre!("%expr: unary `-` is only defined for integers").into()),
+		}
+	}
+	fn cmp(&self, rhs: &Self) -> Result<std::cmp::Ordering, PE> {
+		match (self, rhs) {
+			(Self::Int(a), Self::Int(b)) => Ok(a.cmp(b)),
+			(Self::Str(a), Self::Str(b)) => Ok(a.cmp(b)),
+			(Self::Ver(a), Self::Ver(b)) => Ok(rpm_vercmp(a, b)),
+			_ => Err(eyre!("%expr: cannot compare values of different types").into()),
+		}
+	}
+}
+
+/// A coarse rpm-style version comparator: runs of digits compare
+/// numerically, runs of letters compare lexicographically, and `~`
+/// (tilde) segments sort before everything else, mirroring rpm's
+/// `rpmvercmp` well enough for `%expr`'s `v"..."` literals.
+fn rpm_vercmp(a: &str, b: &str) -> std::cmp::Ordering {
+	#[derive(PartialEq, Eq, PartialOrd, Ord)]
+	enum Segment {
+		Tilde,
+		Num(u64),
+		Alpha(std::string::String),
+	}
+	fn segments(s: &str) -> Vec<Segment> {
+		let mut out = Vec::new();
+		let mut chars = s.chars().peekable();
+		while let Some(&ch) = chars.peek() {
+			if ch == '~' {
+				chars.next();
+				out.push(Segment::Tilde);
+			} else if ch.is_ascii_digit() {
+				let mut n = std::string::String::new();
+				while let Some(&ch) = chars.peek() {
+					if ch.is_ascii_digit() {
+						n.push(ch);
+						chars.next();
+					} else {
+						break;
+					}
+				}
+				out.push(Segment::Num(n.trim_start_matches('0').parse().unwrap_or(0)));
+			} else if ch.is_alphabetic() {
+				let mut w = std::string::String::new();
+				while let Some(&ch) = chars.peek() {
+					if ch.is_alphabetic() {
+						w.push(ch);
+						chars.next();
+					} else {
+						break;
+					}
+				}
+				out.push(Segment::Alpha(w));
+			} else {
+				chars.next();
+			}
+		}
+		out
+	}
+	let (a, b) = (segments(a), segments(b));
+	for (x, y) in a.iter().zip(&b) {
+		match x.cmp(y) {
+			std::cmp::Ordering::Equal => {}
+			ord => return ord,
+		}
+	}
+	// A leftover `~` segment also sorts before the end of the other version.
+	match (a.get(b.len()), b.get(a.len())) {
+		(Some(Segment::Tilde), _) => std::cmp::Ordering::Less,
+		(_, Some(Segment::Tilde)) => std::cmp::Ordering::Greater,
+		_ => a.len().cmp(&b.len()),
+	}
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum ExprToken {
+	Int(i64),
+	Str(String),
+	Ver(String),
+	LParen,
+	RParen,
+	Question,
+	Colon,
+	OrOr,
+	AndAnd,
+	Eq,
+	Ne,
+	Lt,
+	Gt,
+	Le,
+	Ge,
+	Plus,
+	Minus,
+	Star,
+	Slash,
+	Percent,
+	Not,
+}
+
+fn expr_tokenize(input: &str) -> Result<Vec<ExprToken>, PE> {
+	let mut chars = input.chars().peekable();
+	let mut tokens = Vec::new();
+	while let Some(&ch) = chars.peek() {
+		match ch {
+			ch if ch.is_whitespace() => {
+				chars.next();
+			}
+			'(' => {
+				chars.next();
+				tokens.push(ExprToken::LParen);
+			}
+			')' => {
+				chars.next();
+				tokens.push(ExprToken::RParen);
+			}
+			'?' => {
+				chars.next();
+				tokens.push(ExprToken::Question);
+			}
+			':' => {
+				chars.next();
+				tokens.push(ExprToken::Colon);
+			}
+			'+' => {
+				chars.next();
+				tokens.push(ExprToken::Plus);
+			}
+			'-' => {
+				chars.next();
+				tokens.push(ExprToken::Minus);
+			}
+			'*' => {
+				chars.next();
+				tokens.push(ExprToken::Star);
+			}
+			'/' => {
+				chars.next();
+				tokens.push(ExprToken::Slash);
+			}
+			'%' => {
+				chars.next();
+				tokens.push(ExprToken::Percent);
+			}
+			'!' => {
+				chars.next();
+				if chars.peek() == Some(&'=') {
+					chars.next();
+					tokens.push(ExprToken::Ne);
+				} else {
+					tokens.push(ExprToken::Not);
+				}
+			}
+			'=' => {
+				chars.next();
+				#[rustfmt::skip]
+				let Some('=') = chars.next() else {
+					return Err(eyre!("%expr: expected `==`, found a lone `=`").into());
+				};
+				tokens.push(ExprToken::Eq);
+			}
+			'<' => {
+				chars.next();
+				if chars.peek() == Some(&'=') {
+					chars.next();
+					tokens.push(ExprToken::Le);
+				} else {
+					tokens.push(ExprToken::Lt);
+				}
+			}
+			'>' => {
+				chars.next();
+				if chars.peek() == Some(&'=') {
+					chars.next();
+					tokens.push(ExprToken::Ge);
+				} else {
+					tokens.push(ExprToken::Gt);
+				}
+			}
+			'&' => {
+				chars.next();
+				#[rustfmt::skip]
+				let Some('&') = chars.next() else {
+					return Err(eyre!("%expr: expected `&&`").into());
+				};
+				tokens.push(ExprToken::AndAnd);
+			}
+			'|' => {
+				chars.next();
+				#[rustfmt::skip]
+				let Some('|') = chars.next() else {
+					return Err(eyre!("%expr: expected `||`").into());
+				};
+				tokens.push(ExprToken::OrOr);
+			}
+			'"' => {
+				chars.next();
+				tokens.push(ExprToken::Str(expr_read_quoted(&mut chars)?));
+			}
+			'v' if chars.clone().nth(1) == Some('"') => {
+				chars.next();
+				chars.next();
+				tokens.push(ExprToken::Ver(expr_read_quoted(&mut chars)?));
+			}
+			ch if ch.is_ascii_digit() => {
+				let mut n = String::new();
+				while let Some(&ch) = chars.peek() {
+					if ch.is_ascii_digit() {
+						n.push(ch);
+						chars.next();
+					} else {
+						break;
+					}
+				}
+				tokens.push(ExprToken::Int(n.parse().map_err(|_| eyre!("%expr: invalid integer literal `{n}`"))?));
+			}
+			ch => return Err(eyre!("%expr: unexpected character `{ch}`").into()),
+		}
+	}
+	Ok(tokens)
+}
+
+fn expr_read_quoted(chars: &mut std::iter::Peekable<std::str::Chars>) -> Result<String, PE> {
+	let mut s = String::new();
+	loop {
+		match chars.next() {
+			Some('"') => return Ok(s),
+			Some('\\') => match chars.next() {
+				Some(ch) => s.push(ch),
+				None => return Err(eyre!("%expr: unterminated string literal").into()),
+			},
+			Some(ch) => s.push(ch),
+			None => return Err(eyre!("%expr: unterminated string literal").into()),
+		}
+	}
+}
+
+/// Recursive-descent/Pratt parser for `%expr`, evaluating eagerly as it
+/// descends rather than building an intermediate AST.
+struct ExprParser<'a> {
+	tokens: &'a [ExprToken],
+	pos: usize,
+}
+
+impl<'a> ExprParser<'a> {
+	fn new(tokens: &'a [ExprToken]) -> Self {
+		Self { tokens, pos: 0 }
+	}
+	fn peek(&self) -> Option<&ExprToken> {
+		self.tokens.get(self.pos)
+	}
+	fn bump(&mut self) {
+		self.pos += 1;
+	}
+	fn expect(&mut self, tok: &ExprToken) -> Result<(), PE> {
+		if self.peek() == Some(tok) {
+			self.bump();
+			Ok(())
+		} else {
+			Err(eyre!("%expr: expected `{tok:?}`").into())
+		}
+	}
+	fn parse(&mut self) -> Result<ExprValue, PE> {
+		let v = self.ternary()?;
+		if self.pos != self.tokens.len() {
+			return Err(eyre!("%expr: trailing tokens after expression").into());
+		}
+		Ok(v)
+	}
+	fn ternary(&mut self) -> Result<ExprValue, PE> {
+		let cond = self.or()?;
+		if self.peek() == Some(&ExprToken::Question) {
+			self.bump();
+			let t = self.ternary()?;
+			self.expect(&ExprToken::Colon)?;
+			let f = self.ternary()?;
+			Ok(if cond.truthy() { t } else { f })
+		} else {
+			Ok(cond)
+		}
+	}
+	fn or(&mut self) -> Result<ExprValue, PE> {
+		let mut lhs = self.and()?;
+		while self.peek() == Some(&ExprToken::OrOr) {
+			self.bump();
+			let rhs = self.and()?;
+			lhs = ExprValue::Int(i64::from(lhs.truthy() || rhs.truthy()));
+		}
+		Ok(lhs)
+	}
+	fn and(&mut self) -> Result<ExprValue, PE> {
+		let mut lhs = self.comparison()?;
+		while self.peek() == Some(&ExprToken::AndAnd) {
+			self.bump();
+			let rhs = self.comparison()?;
+			lhs = ExprValue::Int(i64::from(lhs.truthy() && rhs.truthy()));
+		}
+		Ok(lhs)
+	}
+	fn comparison(&mut self) -> Result<ExprValue, PE> {
+		let lhs = self.additive()?;
+		#[rustfmt::skip]
+		let Some(tok @ (ExprToken::Eq | ExprToken::Ne | ExprToken::Lt | ExprToken::Gt | ExprToken::Le | ExprToken::Ge)) = self.peek().cloned() else {
+			return Ok(lhs);
+		};
+		self.bump();
+		let rhs = self.additive()?;
+		let ord = lhs.cmp(&rhs)?;
+		Ok(ExprValue::Int(i64::from(match tok {
+			ExprToken::Eq => ord == std::cmp::Ordering::Equal,
+			ExprToken::Ne => ord != std::cmp::Ordering::Equal,
+			ExprToken::Lt => ord == std::cmp::Ordering::Less,
+			ExprToken::Gt => ord == std::cmp::Ordering::Greater,
+			ExprToken::Le => ord != std::cmp::Ordering::Greater,
+			ExprToken::Ge => ord != std::cmp::Ordering::Less,
+			_ => unreachable!(),
+		})))
+	}
+	fn additive(&mut self) -> Result<ExprValue, PE> {
+		let mut lhs = self.multiplicative()?;
+		loop {
+			match self.peek() {
+				Some(ExprToken::Plus) => {
+					self.bump();
+					lhs = lhs.add(self.multiplicative()?)?;
+				}
+				Some(ExprToken::Minus) => {
+					self.bump();
+					lhs = lhs.sub(self.multiplicative()?)?;
+				}
+				_ => break,
+			}
+		}
+		Ok(lhs)
+	}
+	fn multiplicative(&mut self) -> Result<ExprValue, PE> {
+		let mut lhs = self.unary()?;
+		loop {
+			match self.peek() {
+				Some(ExprToken::Star) => {
+					self.bump();
+					lhs = lhs.mul(self.unary()?)?;
+				}
+				Some(ExprToken::Slash) => {
+					self.bump();
+					lhs = lhs.div(self.unary()?)?;
+				}
+				Some(ExprToken::Percent) => {
+					self.bump();
+					lhs = lhs.modulo(self.unary()?)?;
+				}
+				_ => break,
+			}
+		}
+		Ok(lhs)
+	}
+	fn unary(&mut self) -> Result<ExprValue, PE> {
+		match self.peek() {
+			Some(ExprToken::Not) => {
+				self.bump();
+				Ok(ExprValue::Int(i64::from(!self.unary()?.truthy())))
+			}
+			Some(ExprToken::Minus) => {
+				self.bump();
+				self.unary()?.neg()
+			}
+			_ => self.primary(),
+		}
+	}
+	fn primary(&mut self) -> Result<ExprValue, PE> {
+		match self.peek().cloned() {
+			Some(ExprToken::Int(n)) => {
+				self.bump();
+				Ok(ExprValue::Int(n))
+			}
+			Some(ExprToken::Str(s)) => {
+				self.bump();
+				Ok(ExprValue::Str(s))
+			}
+			Some(ExprToken::Ver(s)) => {
+				self.bump();
+				Ok(ExprValue::Ver(s))
+			}
+			Some(ExprToken::LParen) => {
+				self.bump();
+				let v = self.ternary()?;
+				self.expect(&ExprToken::RParen)?;
+				Ok(v)
+			}
+			Some(t) => Err(eyre!("%expr: unexpected token `{t:?}`").into()),
+			None => Err(eyre!("%expr: unexpected end of expression").into()),
+		}
+	}
+}
+
+/// Splits a Lua-macro argument list (e.g. the contents of `%sub{s, i, j}`)
+/// on top-level commas, leaving commas nested inside `{...}`, `(...)` or a
+/// `"..."` string untouched.
+fn split_top_level_args(s: &str) -> Vec<String> {
+	let mut args = Vec::new();
+	let mut depth = 0i32;
+	let mut in_str = false;
+	let mut current = String::new();
+	for ch in s.chars() {
+		match ch {
+			'"' => {
+				in_str = !in_str;
+				current.push(ch);
+			}
+			'{' | '(' if !in_str => {
+				depth += 1;
+				current.push(ch);
+			}
+			'}' | ')' if !in_str => {
+				depth -= 1;
+				current.push(ch);
+			}
+			',' if depth == 0 && !in_str => {
+				args.push(current.trim().into());
+				current = String::new();
+			}
+			_ => current.push(ch),
+		}
+	}
+	args.push(current.trim().into());
+	args
+}
+
+/// One atom of a (greatly simplified) Lua pattern, as understood by
+/// `%gsub`: `.` matches anything, `%a`/`%d` match a Lua character class,
+/// and any other character matches itself literally.
+#[derive(Clone, Copy)]
+enum LuaPatAtom {
+	Lit(char),
+	Any,
+	Alpha,
+	Digit,
+}
+
+impl LuaPatAtom {
+	fn matches(self, ch: char) -> bool {
+		match self {
+			Self::Lit(c) => c == ch,
+			Self::Any => true,
+			Self::Alpha => ch.is_alphabetic(),
+			Self::Digit => ch.is_ascii_digit(),
+		}
+	}
+}
+
+#[derive(Clone, Copy)]
+enum LuaPatQuant {
+	One,
+	ZeroOrMore,
+	OneOrMore,
+}
+
+struct LuaPatItem {
+	atom: LuaPatAtom,
+	quant: LuaPatQuant,
+}
+
+fn compile_lua_pattern(pat: &str) -> Vec<LuaPatItem> {
+	let mut items = Vec::new();
+	let mut chars = pat.chars().peekable();
+	while let Some(ch) = chars.next() {
+		let atom = match ch {
+			'.' => LuaPatAtom::Any,
+			'%' => match chars.next() {
+				Some('a') => LuaPatAtom::Alpha,
+				Some('d') => LuaPatAtom::Digit,
+				Some(c) => LuaPatAtom::Lit(c),
+				None => LuaPatAtom::Lit('%'),
+			},
+			c => LuaPatAtom::Lit(c),
+		};
+		let quant = match chars.peek() {
+			Some('*') => {
+				chars.next();
+				LuaPatQuant::ZeroOrMore
+			}
+			Some('+') => {
+				chars.next();
+				LuaPatQuant::OneOrMore
+			}
+			_ => LuaPatQuant::One,
+		};
+		items.push(LuaPatItem { atom, quant });
+	}
+	items
+}
+
+/// Find the first (leftmost, then longest-greedy) match of `items` in `s`
+/// at or after `from`, returning the matched char range.
+fn match_lua_pattern(s: &[char], items: &[LuaPatItem], from: usize) -> Option<(usize, usize)> {
+	fn try_match(s: &[char], items: &[LuaPatItem], pos: usize) -> Option<usize> {
+		let Some((item, rest)) = items.split_first() else {
+			return Some(pos);
+		};
+		match item.quant {
+			LuaPatQuant::One => {
+				if pos < s.len() && item.atom.matches(s[pos]) {
+					try_match(s, rest, pos + 1)
+				} else {
+					None
+				}
+			}
+			LuaPatQuant::ZeroOrMore | LuaPatQuant::OneOrMore => {
+				let min = usize::from(matches!(item.quant, LuaPatQuant::OneOrMore));
+				let mut run = 0;
+				while pos + run < s.len() && item.atom.matches(s[pos + run]) {
+					run += 1;
+				}
+				while run >= min {
+					if let Some(end) = try_match(s, rest, pos + run) {
+						return Some(end);
+					}
+					if run == 0 {
+						break;
+					}
+					run -= 1;
+				}
+				None
+			}
+		}
+	}
+	(from..=s.len()).find_map(|i| try_match(s, items, i).map(|end| (i, end)))
+}
+
 macro_rules! __internal_macros {
 	($(macro $m:ident($p:ident, $o:ident, $r:ident) $body:block )+) => {
 		$(
@@ -107,7 +698,15 @@ __internal_macros!(
 		Ok(())
 	}
 	macro expr(p, o, r) {
-		todo!()
+		let new_reader = r.range(r.pos..r.end).ok_or_else(|| eyre!("Cannot wind Consumer in %expr"))?;
+		// SAFETY: see the identical downcast in `expand` above.
+		let mut new_reader = *unsafe { Box::from_raw(Box::into_raw(Box::new(new_reader)) as *mut Consumer<std::fs::File>) };
+		let mut expanded = String::new();
+		p.parse_macro(&mut expanded, &mut new_reader)?;
+		let tokens = expr_tokenize(&expanded)?;
+		let value = ExprParser::new(&tokens).parse()?;
+		o.push_str(&value.render());
+		Ok(())
 	}
 	macro lua(p, o, r) {
 		let content: String = r.collect();
@@ -141,8 +740,35 @@ __internal_macros!(
 		o.push('"');
 		Ok(())
 	}
-	macro gsub(p, o, r) {
-		todo!()
+	macro gsub(_p, o, r) {
+		let input: String = r.collect();
+		let args = split_top_level_args(&input);
+		#[rustfmt::skip]
+		let [s, pattern, repl] = <[String; 3]>::try_from(args).map_err(|_| PE::from(eyre!("%gsub: expected 3 arguments {{s, pattern, repl}}")))?;
+		let chars: Vec<char> = s.chars().collect();
+		let items = compile_lua_pattern(&pattern);
+		let mut pos = 0;
+		while pos <= chars.len() {
+			match match_lua_pattern(&chars, &items, pos) {
+				Some((start, end)) => {
+					chars[pos..start].iter().for_each(|ch| o.push(*ch));
+					o.push_str(&repl);
+					pos = if end > start {
+						end
+					} else {
+						if let Some(ch) = chars.get(start) {
+							o.push(*ch);
+						}
+						start + 1
+					};
+				}
+				None => {
+					chars[pos..].iter().for_each(|ch| o.push(*ch));
+					break;
+				}
+			}
+		}
+		Ok(())
 	}
 	macro len(_p, o, r) {
 		o.push_str(&r.collect::<Box<[char]>>().len().to_string());
@@ -153,8 +779,16 @@ __internal_macros!(
 		o.push_str(&r.collect::<String>().to_ascii_lowercase());
 		Ok(())
 	}
-	macro rep(p, o, r) {
-		todo!()
+	macro rep(_p, o, r) {
+		let input: String = r.collect();
+		let args = split_top_level_args(&input);
+		#[rustfmt::skip]
+		let [s, n] = <[String; 2]>::try_from(args).map_err(|_| PE::from(eyre!("%rep: expected 2 arguments {{s, n}}")))?;
+		let n: i64 = n.parse().map_err(|_| eyre!("%rep: `n` must be an integer, got `{n}`"))?;
+		for _ in 0..n.max(0) {
+			o.push_str(&s);
+		}
+		Ok(())
 	}
 	macro reverse(_p, o, r) {
 		let mut chs = r.collect::<Box<[char]>>();
@@ -162,8 +796,22 @@ __internal_macros!(
 		chs.into_iter().for_each(|ch| o.push(*ch));
 		Ok(())
 	}
-	macro sub(p, o, r) {
-		todo!()
+	macro sub(_p, o, r) {
+		let input: String = r.collect();
+		let args = split_top_level_args(&input);
+		#[rustfmt::skip]
+		let [s, i, j] = <[String; 3]>::try_from(args).map_err(|_| PE::from(eyre!("%sub: expected 3 arguments {{s, i, j}}")))?;
+		let i: i64 = i.parse().map_err(|_| eyre!("%sub: `i` must be an integer, got `{i}`"))?;
+		let j: i64 = j.parse().map_err(|_| eyre!("%sub: `j` must be an integer, got `{j}`"))?;
+		let chars: Box<[char]> = s.chars().collect();
+		let len = chars.len() as i64;
+		let norm = |idx: i64| if idx < 0 { len + idx + 1 } else { idx };
+		let start = norm(i).max(1);
+		let end = norm(j).min(len);
+		if start <= end {
+			chars[(start - 1) as usize..end as usize].iter().for_each(|ch| o.push(*ch));
+		}
+		Ok(())
 	}
 	macro upper(_p, o, r) {
 		// assume it's ascii?
@@ -244,7 +892,18 @@ __internal_macros!(
 	}
 	macro uncompress(p, o, r) {
 		//? https://github.com/rpm-software-management/rpm/blob/master/tools/rpmuncompress.c#L69
-		todo!()
+		let new_reader = r.range(r.pos..r.end).ok_or_else(|| eyre!("Cannot wind Consumer in %uncompress"))?;
+		// SAFETY: see the identical downcast in `expand` above.
+		let mut new_reader = *unsafe { Box::from_raw(Box::into_raw(Box::new(new_reader)) as *mut Consumer<std::fs::File>) };
+		let mut path = String::new();
+		p.parse_macro(&mut path, &mut new_reader)?;
+
+		let mut magic = [0u8; 8];
+		let n = std::fs::File::open(&*path).and_then(|mut f| f.read(&mut magic)).map_err(|e| eyre!("%uncompress: failed to read `{path}`: {e}"))?;
+		let magic = &magic[..n];
+		let quoted = shescape_str(&path);
+		o.push_str(&uncompress_command(magic, &quoted));
+		Ok(())
 	}
 	macro getncpus(_p, o, r) {
 		if r.next().is_some() {
@@ -286,9 +945,8 @@ __internal_macros!(
 		tracing::error!("{}", r.collect::<String>());
 		Ok(())
 	}
-	macro verbose(_p, o, _r) {
-		// FIXME
-		o.push('0');
+	macro verbose(p, o, _r) {
+		o.push(if p.trace_enabled { '1' } else { '0' });
 		Ok(())
 	}
 	macro S(p, o, r) {
@@ -303,8 +961,17 @@ __internal_macros!(
 		r.for_each(|c| o.push(c));
 		Ok(())
 	}
-	macro trace(p, o, r) {
-		todo!()
+	macro trace(p, _o, r) {
+		let arg: String = r.collect();
+		let enabled = match arg.trim() {
+			"" => !p.trace_enabled,
+			"1" | "on" | "true" => true,
+			"0" | "off" | "false" => false,
+			other => return Err(eyre!("%trace: unrecognized argument `{other}`, expected `on`, `off`, or empty to toggle").into()),
+		};
+		p.trace_enabled = enabled;
+		tracing::info!(enabled, "%trace: macro-expansion tracing {}", if enabled { "enabled" } else { "disabled" });
+		Ok(())
 	}
 	macro dump(p, _o, r) {
 		let args = r.collect::<String>();
@@ -331,4 +998,388 @@ __internal_macros!(
 		}
 		Ok(())
 	}
-);
\ No newline at end of file
+);
+
+/// Picks the decompression command for `%uncompress`'s `quoted` (an
+/// already shell-quoted path), based on `magic`'s leading bytes.
+///
+/// `magic` may be shorter than any of the signatures below (e.g. for a
+/// file under 8 bytes long) — `starts_with` simply fails to match rather
+/// than panicking, so an unrecognized or too-short header falls through
+/// to a plain `cat`.
+#[rustfmt::skip]
+fn uncompress_command(magic: &[u8], quoted: &str) -> std::string::String {
+	if magic.starts_with(&[0x1f, 0x8b]) { format!("gzip -dc {quoted}") }
+	else if magic.starts_with(b"BZh") { format!("bzip2 -dc {quoted}") }
+	else if magic.starts_with(&[0xfd, 0x37, 0x7a, 0x58, 0x5a, 0x00]) { format!("xz -dc {quoted}") }
+	else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) { format!("zstd -dc {quoted}") }
+	else if magic.starts_with(b"LZIP") { format!("lzip -dc {quoted}") }
+	else if magic.starts_with(&[0x04, 0x22, 0x4d, 0x18]) { format!("lz4 -dc {quoted}") }
+	else if magic.starts_with(&[0x1f, 0x9d]) { format!("uncompress -c {quoted}") }
+	else if magic.starts_with(&[0x50, 0x4b, 0x03, 0x04]) { format!("unzip -p {quoted}") }
+	else { format!("cat {quoted}") }
+}
+
+/// Single-quotes `s` for safe interpolation into a shell command line,
+/// using the exact same escaping as the `%shescape` macro above.
+fn shescape_str(s: &str) -> String {
+	let mut out = String::new();
+	out.push('\'');
+	for ch in s.chars() {
+		if ch == '\'' {
+			out.push('\'');
+			out.push('\\');
+			out.push('\'');
+		}
+		out.push(ch);
+	}
+	out.push('\'');
+	out
+}
+
+/// Executes rpm's `%(command)` shell-expansion syntax.
+///
+/// Unlike the macros above, `%(...)` is not dispatched by name through
+/// [`INTERNAL_MACROS`] — `SpecParser::parse_macro` hooks into this
+/// directly whenever it sees a bare `%(` instead of `%{...}` or a named
+/// macro invocation. The command text is macro-expanded first, then
+/// checked for embedded NUL bytes (which `Command::spawn` would otherwise
+/// reject with an opaque OS-level error) before being handed to `sh -c`.
+pub(crate) fn shell_expand(p: &mut SpecParser, o: &mut String, r: &mut Consumer<dyn Read + '_>) -> Result<(), PE> {
+	let new_reader = r.range(r.pos..r.end).ok_or_else(|| eyre!("Cannot wind Consumer in %(...)"))?;
+	// SAFETY: see the identical downcast in `expand` (in `__internal_macros!` above).
+	let mut new_reader = *unsafe { Box::from_raw(Box::into_raw(Box::new(new_reader)) as *mut Consumer<std::fs::File>) };
+	let mut cmd = String::new();
+	p.parse_macro(&mut cmd, &mut new_reader)?;
+	reject_nul_byte(&cmd)?;
+
+	let output = std::process::Command::new("sh")
+		.arg("-c")
+		.arg(&*cmd)
+		.output()
+		.map_err(|e| eyre!("%(...): failed to spawn `sh -c {cmd:?}`: {e}"))?;
+	if !output.status.success() {
+		tracing::warn!(command = %cmd, status = %output.status, "%(...) command exited with a nonzero status");
+	}
+	let mut stdout = std::string::String::from_utf8_lossy(&output.stdout).into_owned();
+	if stdout.ends_with('\n') {
+		stdout.pop();
+	}
+	o.push_str(&stdout);
+	Ok(())
+}
+
+/// Rejects `cmd` if it contains an embedded NUL byte, which
+/// `Command::spawn` would otherwise reject with an opaque OS-level error.
+fn reject_nul_byte(cmd: &str) -> Result<(), PE> {
+	if let Some(pos) = cmd.find('\0') {
+		return Err(eyre!("%(...): command contains a NUL byte at position {pos}, which `sh` cannot accept").into());
+	}
+	Ok(())
+}
+
+/// The `RPMSPEC_METRICS_DIR` environment variable, if set, names a
+/// directory that [`record_expansion`] appends a JSON-lines expansion
+/// trace to — this is independent of `%trace`, which only controls the
+/// human-readable log output.
+fn metrics_dir() -> Option<std::path::PathBuf> {
+	std::env::var_os("RPMSPEC_METRICS_DIR").map(std::path::PathBuf::from)
+}
+
+fn json_escape(s: &str) -> std::string::String {
+	let mut out = std::string::String::with_capacity(s.len());
+	for ch in s.chars() {
+		match ch {
+			'"' => out.push_str("\\\""),
+			'\\' => out.push_str("\\\\"),
+			'\n' => out.push_str("\\n"),
+			'\t' => out.push_str("\\t"),
+			c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+			c => out.push(c),
+		}
+	}
+	out
+}
+
+/// Records one macro-expansion event, for `%trace` and the metrics-dir.
+///
+/// `SpecParser::parse_macro` calls this around every macro expansion
+/// (internal or user-defined), passing the macro's name, the source it
+/// was invoked from, its byte range within that source, the current
+/// recursion depth, and the text before/after expansion. When
+/// `p.trace_enabled` is set (toggled by `%trace`) this logs a
+/// human-readable trace line; independently, when `RPMSPEC_METRICS_DIR` is
+/// set, a JSON line is appended to `<dir>/expansions.jsonl` for
+/// post-mortem debugging of pathological recursive macros and for
+/// profiling which macros dominate parse time.
+pub(crate) fn record_expansion(p: &SpecParser, name: &str, file: &Path, src: &str, offset: usize, len: usize, depth: usize, before: &str, after: &str, elapsed: std::time::Duration) {
+	if p.trace_enabled {
+		let (line, col) = crate::diagnostics::line_col(src, offset);
+		tracing::debug!(
+			macro_name = name,
+			location = %format!("{}:{line}:{col}", file.display()),
+			depth,
+			before,
+			after,
+			elapsed_us = elapsed.as_micros(),
+			"macro expansion",
+		);
+	}
+
+	let Some(dir) = metrics_dir() else { return };
+	let record = format!(
+		"{{\"name\":\"{}\",\"file\":\"{}\",\"offset\":{offset},\"len\":{len},\"depth\":{depth},\"elapsed_us\":{}}}\n",
+		json_escape(name),
+		json_escape(&file.display().to_string()),
+		elapsed.as_micros(),
+	);
+	let result = std::fs::OpenOptions::new().create(true).append(true).open(dir.join("expansions.jsonl")).and_then(|mut f| f.write_all(record.as_bytes()));
+	if let Err(e) = result {
+		tracing::warn!(error = %e, "failed to append macro-expansion metrics record");
+	}
+}
+
+#[cfg(test)]
+mod json_escape_tests {
+	use super::json_escape;
+
+	#[test]
+	fn escapes_quotes_and_backslashes() {
+		assert_eq!(json_escape("say \"hi\"\\"), "say \\\"hi\\\"\\\\");
+	}
+
+	#[test]
+	fn escapes_newline_and_tab_as_short_forms() {
+		assert_eq!(json_escape("a\nb\tc"), "a\\nb\\tc");
+	}
+
+	#[test]
+	fn escapes_other_control_characters_as_unicode_codepoints() {
+		assert_eq!(json_escape("\u{1}\u{1f}"), "\\u0001\\u001f");
+	}
+
+	#[test]
+	fn leaves_ordinary_text_untouched() {
+		assert_eq!(json_escape("hello, world"), "hello, world");
+	}
+}
+
+#[cfg(test)]
+mod trace_tests {
+	use super::*;
+
+	/// Runs `src` through a fresh [`SpecParser`] and returns the expanded
+	/// output, panicking if expansion failed.
+	fn expand_ok(src: &str) -> std::string::String {
+		let mut p = SpecParser::default();
+		let mut o = String::new();
+		let mut r: Consumer<std::fs::File> = Consumer::new(Arc::new(Mutex::new(src.into())), None, Arc::from(Path::new("test.spec")));
+		p.parse_macro(&mut o, &mut r).expect("expected successful expansion");
+		o.to_string()
+	}
+
+	/// `%verbose` reads `trace_enabled`, which starts `false`.
+	#[test]
+	fn verbose_defaults_to_off() {
+		assert_eq!(expand_ok("%verbose"), "0");
+	}
+
+	/// `%trace` with no argument toggles the current state.
+	#[test]
+	fn trace_with_no_argument_toggles() {
+		assert_eq!(expand_ok("%trace%verbose"), "1");
+		assert_eq!(expand_ok("%trace%trace%verbose"), "0");
+	}
+
+	/// `%trace` accepts `on`/`1`/`true` and `off`/`0`/`false` explicitly,
+	/// regardless of the current state.
+	#[test]
+	fn trace_accepts_on_and_off_spellings() {
+		assert_eq!(expand_ok("%{trace:on}%verbose"), "1");
+		assert_eq!(expand_ok("%{trace:1}%verbose"), "1");
+		assert_eq!(expand_ok("%{trace:true}%verbose"), "1");
+		assert_eq!(expand_ok("%{trace:on}%{trace:off}%verbose"), "0");
+		assert_eq!(expand_ok("%{trace:on}%{trace:0}%verbose"), "0");
+		assert_eq!(expand_ok("%{trace:on}%{trace:false}%verbose"), "0");
+	}
+
+	/// An unrecognized argument is a hard error, not a silent no-op.
+	#[test]
+	fn trace_rejects_unrecognized_arguments() {
+		let mut p = SpecParser::default();
+		let mut o = String::new();
+		let mut r: Consumer<std::fs::File> = Consumer::new(Arc::new(Mutex::new("%{trace:maybe}".into())), None, Arc::from(Path::new("test.spec")));
+		let err = p.parse_macro(&mut o, &mut r).expect_err("expected %trace to reject an unrecognized argument");
+		assert!(err.to_string().contains("maybe"));
+	}
+
+	/// `%trace` and `%verbose` round-trip through a full `parse_macro` pass:
+	/// enabling tracing makes `%verbose` observe it, and disabling it again
+	/// flips `%verbose` back, regardless of how many times it toggles.
+	#[test]
+	fn trace_and_verbose_round_trip() {
+		assert_eq!(expand_ok("%verbose%trace%verbose%trace%verbose"), "010");
+	}
+}
+
+#[cfg(test)]
+mod expr_tests {
+	use super::{expr_tokenize, ExprParser, ExprValue};
+
+	fn eval(src: &str) -> ExprValue {
+		let tokens = expr_tokenize(src).unwrap();
+		ExprParser::new(&tokens).parse().unwrap()
+	}
+
+	#[test]
+	fn arithmetic_respects_precedence() {
+		assert_eq!(eval("2 + 3 * 4"), ExprValue::Int(14));
+		assert_eq!(eval("(2 + 3) * 4"), ExprValue::Int(20));
+		assert_eq!(eval("2 * 3 + 4 * 5"), ExprValue::Int(26));
+		assert_eq!(eval("10 - 2 - 3"), ExprValue::Int(5));
+	}
+
+	#[test]
+	fn comparison_binds_looser_than_arithmetic() {
+		assert_eq!(eval("1 + 1 == 2"), ExprValue::Int(1));
+		assert_eq!(eval("1 + 1 != 2"), ExprValue::Int(0));
+	}
+
+	#[test]
+	fn logical_operators_short_circuit_precedence() {
+		// `&&` binds tighter than `||`, so this is `0 || (1 && 0)` == 0.
+		assert_eq!(eval("0 || 1 && 0"), ExprValue::Int(0));
+		assert_eq!(eval("1 || 0 && 0"), ExprValue::Int(1));
+	}
+
+	#[test]
+	fn ternary_is_right_associative_and_lowest_precedence() {
+		assert_eq!(eval("1 ? 2 : 0 ? 3 : 4"), ExprValue::Int(2));
+		assert_eq!(eval("0 ? 2 : 1 ? 3 : 4"), ExprValue::Int(3));
+	}
+
+	#[test]
+	fn unary_minus_and_not() {
+		assert_eq!(eval("-3 + 5"), ExprValue::Int(2));
+		assert_eq!(eval("!0"), ExprValue::Int(1));
+		assert_eq!(eval("!5"), ExprValue::Int(0));
+	}
+
+	#[test]
+	fn strings_concatenate_but_do_not_subtract() {
+		assert_eq!(eval(r#""foo" + "bar""#), ExprValue::Str("foobar".into()));
+		let tokens = expr_tokenize(r#""foo" - "bar""#).unwrap();
+		assert!(ExprParser::new(&tokens).parse().is_err());
+	}
+
+	#[test]
+	fn mixing_int_and_string_is_a_type_error() {
+		let tokens = expr_tokenize(r#"1 + "a""#).unwrap();
+		assert!(ExprParser::new(&tokens).parse().is_err());
+		let tokens = expr_tokenize(r#"1 == "a""#).unwrap();
+		assert!(ExprParser::new(&tokens).parse().is_err());
+	}
+
+	#[test]
+	fn division_and_modulo_by_zero_error() {
+		assert!(ExprParser::new(&expr_tokenize("1 / 0").unwrap()).parse().is_err());
+		assert!(ExprParser::new(&expr_tokenize("1 % 0").unwrap()).parse().is_err());
+	}
+}
+
+#[cfg(test)]
+mod lua_pattern_tests {
+	use super::{compile_lua_pattern, match_lua_pattern};
+
+	fn find(s: &str, pat: &str) -> Option<(usize, usize)> {
+		let chars: Vec<char> = s.chars().collect();
+		let items = compile_lua_pattern(pat);
+		match_lua_pattern(&chars, &items, 0)
+	}
+
+	#[test]
+	fn star_is_greedy_but_backtracks_to_let_the_rest_match() {
+		// Greedy `.*` first grabs the whole string, then backtracks until
+		// the trailing literal `b` can match the *last* `b` in "axxbxxb".
+		assert_eq!(find("axxbxxb", "a.*b"), Some((0, 7)));
+	}
+
+	#[test]
+	fn star_backtracks_past_false_starts_in_the_middle() {
+		// `%d*` greedily eats "123", then must backtrack one digit at a
+		// time before the literal "3x" can match.
+		assert_eq!(find("a123x", "a%d*3x"), Some((0, 5)));
+	}
+
+	#[test]
+	fn zero_or_more_matches_empty_run_when_nothing_else_fits() {
+		assert_eq!(find("abc", "%d*a"), Some((0, 1)));
+	}
+
+	#[test]
+	fn one_or_more_requires_at_least_one_match() {
+		assert_eq!(find("abc", "%d+a"), None);
+		assert_eq!(find("1a", "%d+a"), Some((0, 2)));
+	}
+
+	#[test]
+	fn leftmost_match_wins_over_a_later_longer_one() {
+		assert_eq!(find("xaybzc", "%a"), Some((0, 1)));
+		assert_eq!(find("x1y22", "%a%d+"), Some((0, 2)));
+	}
+
+	#[test]
+	fn character_classes_and_any_dot() {
+		assert_eq!(find("12ab", "%d%d%a%a"), Some((0, 4)));
+		assert_eq!(find("x", "."), Some((0, 1)));
+	}
+
+	#[test]
+	fn no_match_returns_none() {
+		assert_eq!(find("abc", "%d+"), None);
+	}
+}
+
+#[cfg(test)]
+mod uncompress_tests {
+	use super::uncompress_command;
+
+	#[test]
+	fn recognizes_each_supported_magic() {
+		assert_eq!(uncompress_command(&[0x1f, 0x8b, 0, 0], "'f'"), "gzip -dc 'f'");
+		assert_eq!(uncompress_command(b"BZh9", "'f'"), "bzip2 -dc 'f'");
+		assert_eq!(uncompress_command(&[0xfd, 0x37, 0x7a, 0x58, 0x5a, 0x00], "'f'"), "xz -dc 'f'");
+		assert_eq!(uncompress_command(&[0x28, 0xb5, 0x2f, 0xfd], "'f'"), "zstd -dc 'f'");
+		assert_eq!(uncompress_command(b"LZIP", "'f'"), "lzip -dc 'f'");
+		assert_eq!(uncompress_command(&[0x04, 0x22, 0x4d, 0x18], "'f'"), "lz4 -dc 'f'");
+		assert_eq!(uncompress_command(&[0x1f, 0x9d], "'f'"), "uncompress -c 'f'");
+		assert_eq!(uncompress_command(&[0x50, 0x4b, 0x03, 0x04], "'f'"), "unzip -p 'f'");
+	}
+
+	#[test]
+	fn unrecognized_magic_falls_back_to_cat() {
+		assert_eq!(uncompress_command(&[0, 0, 0, 0], "'f'"), "cat 'f'");
+	}
+
+	#[test]
+	fn short_file_does_not_panic_and_falls_back_to_cat() {
+		assert_eq!(uncompress_command(&[], "'f'"), "cat 'f'");
+		assert_eq!(uncompress_command(&[0x1f], "'f'"), "cat 'f'");
+	}
+}
+
+#[cfg(test)]
+mod shell_expand_tests {
+	use super::reject_nul_byte;
+
+	#[test]
+	fn accepts_a_command_without_nul_bytes() {
+		assert!(reject_nul_byte("echo hi").is_ok());
+	}
+
+	#[test]
+	fn rejects_an_embedded_nul_byte_at_its_position() {
+		let err = reject_nul_byte("echo\0hi").unwrap_err();
+		assert!(err.to_string().contains("position 4"));
+	}
+}
\ No newline at end of file
@@ -0,0 +1,301 @@
+//! The core macro-expansion scanner.
+//!
+//! [`SpecParser::parse_macro`] walks a character stream, copying literal
+//! text to the output buffer and dispatching `%name`, `%{name ...}`,
+//! `%(...)`, and `%[...]` constructs as it encounters them. It is the
+//! single place that both [`INTERNAL_MACROS`] and user-defined macros are
+//! actually invoked, which makes it the right place to wrap a failing
+//! expansion in a [`diagnostics::annotate`]'d error — see that function's
+//! doc comment for how a nested re-entrant failure (a macro that re-enters
+//! `parse_macro` for its own operand text, e.g. `expr`, `expand`,
+//! `uncompress`, `shell_expand`) avoids being annotated twice.
+
+use crate::{
+	diagnostics,
+	error::ParserError as PE,
+	macros::{self, MacroType, INTERNAL_MACROS},
+	util::Consumer,
+};
+use color_eyre::eyre::eyre;
+use smartstring::alias::String;
+use std::{collections::HashMap, io::Read, path::Path, sync::Arc, time::Instant};
+
+#[derive(Default)]
+pub struct SpecParser {
+	pub macros: HashMap<String, Vec<MacroType>>,
+	/// Toggled by `%trace`; read by `%verbose` and consulted by
+	/// [`macros::record_expansion`] to decide whether to log each expansion.
+	pub(crate) trace_enabled: bool,
+	/// Current macro-expansion recursion depth, maintained by
+	/// [`Self::dispatch`] and reported alongside every trace/metrics record.
+	trace_depth: usize,
+}
+
+impl SpecParser {
+	pub fn define_macro(&mut self, name: String, body: Consumer<impl Read>, param: bool, len: usize) {
+		let _ = (body, param, len);
+		self.macros.entry(name).or_default();
+		// ... existing definition bookkeeping, untouched by this series.
+	}
+
+	pub fn load_macro_from_file(&mut self, _path: &Path) -> Result<(), PE> {
+		// ... existing file-loading logic, untouched by this series.
+		Ok(())
+	}
+
+	/// Scans `r`, copying literal text to `o` and expanding `%...`
+	/// constructs it encounters along the way.
+	pub fn parse_macro<R: Read>(&mut self, o: &mut String, r: &mut Consumer<R>) -> Result<(), PE> {
+		while let Some(ch) = r.next() {
+			if ch != '%' {
+				o.push(ch);
+				continue;
+			}
+			let pct_pos = r.pos - 1;
+			match r.next() {
+				Some('(') => {
+					let (arg_start, arg_end) = find_matching(r, '(', ')');
+					self.dispatch(o, r, pct_pos, arg_start, arg_end, "(...)", macros::shell_expand)?;
+				}
+				Some('[') => {
+					// `%[...]` is sugar for `%{expr:...}` — same evaluator,
+					// just dispatched straight off its own delimiters instead
+					// of going through `dispatch_named`'s name/`:` parsing.
+					let (arg_start, arg_end) = find_matching(r, '[', ']');
+					let MacroType::Internal(f) = INTERNAL_MACROS.get("expr").and_then(|v| v.last().cloned()).expect("`expr` is always registered in INTERNAL_MACROS") else {
+						unreachable!("`expr` is always registered as MacroType::Internal")
+					};
+					self.dispatch(o, r, pct_pos, arg_start, arg_end, "[...]", f)?;
+				}
+				Some(_) => {
+					r.back();
+					r.back();
+					self.dispatch_named(o, r, pct_pos)?;
+				}
+				None => o.push('%'),
+			}
+		}
+		Ok(())
+	}
+
+	/// Runs a single internal-macro invocation over the byte range
+	/// `[arg_start, arg_end)` of `r`'s underlying buffer, timing it,
+	/// reporting it to [`macros::record_expansion`], and annotating any
+	/// error it returns with the span it was invoked from. This is the
+	/// *only* call site for [`MacroType::Internal`] functions — see
+	/// [`diagnostics::annotate`]'s doc comment for how a nested re-entrant
+	/// failure avoids being annotated twice.
+	fn dispatch<R: Read>(
+		&mut self,
+		o: &mut String,
+		r: &mut Consumer<R>,
+		start: usize,
+		arg_start: usize,
+		arg_end: usize,
+		name: &str,
+		f: fn(&mut Self, &mut String, &mut Consumer<dyn Read + '_>) -> Result<(), PE>,
+	) -> Result<(), PE> {
+		let new_reader = r.range(arg_start..arg_end).ok_or_else(|| eyre!("Cannot wind Consumer for %{name}"))?;
+		// Safe: `Consumer<R>` -> `Consumer<dyn Read + '_>` is a plain
+		// unsizing coercion (R only ever appears in the trailing reader
+		// field), unlike the reverse direction used elsewhere in this
+		// crate, which needs an unsafe re-interpretation instead.
+		let mut boxed: Box<Consumer<dyn Read + '_>> = Box::new(new_reader);
+		let file = boxed.file.clone();
+		let before_len = o.len();
+
+		self.trace_depth += 1;
+		let t0 = Instant::now();
+		let result = f(self, o, &mut boxed).map_err(|e| diagnostics::annotate(&*boxed, start, name, e));
+		self.trace_depth -= 1;
+		let elapsed = t0.elapsed();
+
+		// Only clone the whole source buffer when `%trace` actually needs it
+		// (`record_expansion`'s metrics-dir branch doesn't touch `src`). This
+		// is checked after `f` runs, since `f` may be `%trace` itself.
+		let src = if self.trace_enabled { boxed.s.lock().clone() } else { String::new() };
+		let before = if self.trace_enabled { src.get(start..arg_end).unwrap_or_default() } else { "" };
+		macros::record_expansion(self, name, &file, &src, start, arg_end.saturating_sub(start), self.trace_depth, before, &o[before_len..], elapsed);
+		result
+	}
+
+	/// Resolves and runs a `%name`/`%{name ...}` invocation, whether it
+	/// comes from [`INTERNAL_MACROS`] or a user `%define`.
+	fn dispatch_named<R: Read>(&mut self, o: &mut String, r: &mut Consumer<R>, pct_pos: usize) -> Result<(), PE> {
+		r.next(); // consume '%'
+		let braced = r.next() == Some('{');
+		if !braced {
+			r.back();
+		}
+
+		let mut name = String::new();
+		while let Some(ch) = r.next() {
+			if ch.is_alphanumeric() || ch == '_' {
+				name.push(ch);
+			} else {
+				// `:` is the arg separator for `%{name:arg}` — consume it so
+				// `find_matching` starts right after it instead of on top of
+				// it (which would otherwise leave a leading `:` in every
+				// extracted argument). Any other terminator (`}`, end of an
+				// unbraced name, ...) isn't ours to consume.
+				if !(braced && ch == ':') {
+					r.back();
+				}
+				break;
+			}
+		}
+		if name.is_empty() {
+			o.push('%');
+			return Ok(());
+		}
+
+		let (arg_start, arg_end) = if braced { find_matching(r, '{', '}') } else { (r.pos, r.pos) };
+
+		let mt = self
+			.macros
+			.get(&name)
+			.and_then(|v| v.last().cloned())
+			.or_else(|| INTERNAL_MACROS.get(&name).and_then(|v| v.last().cloned()))
+			.ok_or_else(|| PE::MacroNotFound(name.clone()))?;
+
+		let result = match mt {
+			MacroType::Internal(f) => self.dispatch(o, r, pct_pos, arg_start, arg_end, &name, f),
+			MacroType::Runtime { file, offset, len, s, .. } => {
+				// The macro's own defined body, not the call site — `pct_pos`
+				// is an offset into the *caller's* buffer, which isn't this.
+				let def_src = if self.trace_enabled { s.lock().clone() } else { String::new() };
+				let before = if self.trace_enabled { def_src.get(offset..offset + len).unwrap_or_default() } else { "" };
+				let before_len = o.len();
+				self.trace_depth += 1;
+				let t0 = Instant::now();
+				let mut body: Consumer<std::fs::File> = Consumer::new(Arc::clone(&s), None, Arc::clone(&file));
+				body.pos = offset;
+				body.end = offset + len;
+				// Annotate against `r`, the call site `pct_pos` is actually an
+				// offset into — not `body`, which is windowed onto the macro's
+				// own (possibly different, possibly shorter) definition buffer.
+				let result = self.parse_macro(o, &mut body).map_err(|e| diagnostics::annotate(&*r, pct_pos, &name, e));
+				self.trace_depth -= 1;
+				// Same mismatch applies to `record_expansion`'s line/col lookup:
+				// resolve it against the call site's buffer, not the macro's.
+				let call_src = if self.trace_enabled { r.s.lock().clone() } else { String::new() };
+				macros::record_expansion(self, &name, &r.file, &call_src, pct_pos, arg_end.saturating_sub(pct_pos), self.trace_depth, before, &o[before_len..], t0.elapsed());
+				result
+			}
+		};
+		result
+	}
+}
+
+/// Scans `r` (already positioned just past the opening `open` delimiter)
+/// for the matching `close`, respecting nesting, and returns the byte
+/// range of the text between them. `r.pos` ends up just past `close` (or
+/// at `r.end` if no match is found).
+fn find_matching<R: Read>(r: &mut Consumer<R>, open: char, close: char) -> (usize, usize) {
+	let start = r.pos;
+	let mut depth = 1i32;
+	while let Some(ch) = r.next() {
+		if ch == open {
+			depth += 1;
+		} else if ch == close {
+			depth -= 1;
+			if depth == 0 {
+				return (start, r.pos - 1);
+			}
+		}
+	}
+	(start, r.pos)
+}
+
+/// Shared fixtures for the `#[cfg(test)]` modules below, which all need to
+/// run a string through a fresh [`SpecParser`] and either its successful
+/// output or its failure.
+#[cfg(test)]
+mod test_support {
+	use super::*;
+	use parking_lot::Mutex;
+
+	/// Runs `src` through a fresh [`SpecParser`] and returns the expanded
+	/// output, panicking if expansion failed.
+	pub(super) fn expand_ok(src: &str) -> std::string::String {
+		let mut p = SpecParser::default();
+		let mut o = String::new();
+		let mut r: Consumer<std::fs::File> = Consumer::new(Arc::new(Mutex::new(src.into())), None, Arc::from(Path::new("test.spec")));
+		p.parse_macro(&mut o, &mut r).expect("expected successful expansion");
+		o.to_string()
+	}
+
+	/// Runs `src` through a fresh [`SpecParser`] and returns the rendered
+	/// error, if expansion failed.
+	pub(super) fn expand_err(src: &str) -> PE {
+		let mut p = SpecParser::default();
+		let mut o = String::new();
+		let mut r: Consumer<std::fs::File> = Consumer::new(Arc::new(Mutex::new(src.into())), None, Arc::from(Path::new("test.spec")));
+		p.parse_macro(&mut o, &mut r).expect_err("expected a macro-expansion failure")
+	}
+}
+
+#[cfg(test)]
+mod nested_annotation_tests {
+	use super::test_support::expand_err;
+
+	/// Counts rendered-snippet origin markers (`--> file:line:col`) in
+	/// `rendered`, the same way [`diagnostics::is_rendered_snippet`] detects
+	/// one. The left gutter before `-->` is padded to the snippet's line-
+	/// number width, so it isn't a fixed number of spaces.
+	fn count_origin_markers(rendered: &str) -> usize {
+		rendered.lines().filter(|line| line.trim_start().starts_with("--> ")).count()
+	}
+
+	/// A macro that recurses through `parse_macro` (`%expr`) whose operand
+	/// fails at a *nested* macro call (`%{rep:x}`, missing its second
+	/// argument) must be annotated exactly once — at the inner failure —
+	/// not once more when it bubbles up through `%expr`'s own dispatch.
+	#[test]
+	fn nested_failure_through_expr_is_annotated_once() {
+		let rendered = expand_err("%{expr:%{rep:x}}").to_string();
+		assert_eq!(count_origin_markers(&rendered), 1, "expected exactly one rendered snippet, got:\n{rendered}");
+	}
+
+	/// Same, but through `%(...)` shell expansion re-entering `parse_macro`.
+	#[test]
+	fn nested_failure_through_shell_expand_is_annotated_once() {
+		let rendered = expand_err("%(%{rep:x})").to_string();
+		assert_eq!(count_origin_markers(&rendered), 1, "expected exactly one rendered snippet, got:\n{rendered}");
+	}
+}
+
+#[cfg(test)]
+mod braced_arg_tests {
+	use super::test_support::expand_ok;
+
+	/// `%{name:arg}` must hand the dispatched macro `arg`, not `:arg` — the
+	/// name scan that finds where `name` ends must consume the `:`
+	/// separator itself rather than leaving it for `find_matching` to pick
+	/// up as the first character of the argument span.
+	#[test]
+	fn braced_macro_arg_has_no_leading_colon() {
+		assert_eq!(expand_ok("%{expr:1+1}"), "2");
+		assert_eq!(expand_ok("%{rep:x,3}"), "xxx");
+		assert_eq!(expand_ok("%{sub:hello,1,3}"), "hel");
+	}
+
+	/// A no-argument braced macro (no `:` at all) must still work: the name
+	/// scan backs up onto `}` so `find_matching` sees an empty argument.
+	#[test]
+	fn braced_macro_with_no_args_still_works() {
+		assert_eq!(expand_ok("%{verbose}"), "0");
+	}
+}
+
+#[cfg(test)]
+mod bracket_expr_tests {
+	use super::test_support::expand_ok;
+
+	/// `%[...]` is sugar for `%{expr:...}` and must go through the same
+	/// evaluator.
+	#[test]
+	fn bracket_expr_is_evaluated() {
+		assert_eq!(expand_ok("%[1+1] %[(2+3)*4]"), "2 20");
+	}
+}